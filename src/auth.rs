@@ -0,0 +1,156 @@
+use bcrypt::verify;
+
+use chrono::{Duration, Utc};
+
+use jsonwebtoken::{decode, encode, Header, Validation};
+
+use mongodb::{doc, Client, ThreadedClient};
+use mongodb::db::ThreadedDatabase;
+
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket_contrib::json::Json;
+
+const DATABASE: &'static str = "gps";
+const USERS_COLLECTION: &'static str = "users";
+const TOKEN_TTL_HOURS: i64 = 24;
+
+/// HMAC secret used to sign and verify login tokens, loaded once at startup and
+/// handed to Rocket as managed state.
+pub struct JwtSecret(pub String);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    uid: u64,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub id: u64,
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    Missing,
+    Malformed(Box<dyn std::error::Error>),
+    Invalid,
+    Expired,
+    InvalidSignature,
+}
+
+fn issue_token(uid: u64, secret: &str) -> jsonwebtoken::errors::Result<String> {
+    let now = Utc::now();
+    let claims = Claims {
+        uid,
+        iat: now.timestamp(),
+        exp: (now + Duration::hours(TOKEN_TTL_HOURS)).timestamp(),
+    };
+    encode(&Header::default(), &claims, secret.as_ref())
+}
+
+fn decode_claims(token: &str, secret: &str) -> Result<Claims, UserError> {
+    decode::<Claims>(token, secret.as_ref(), &Validation::default())
+        .map(|data| data.claims)
+        .map_err(|err| match err.into_kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => UserError::Expired,
+            jsonwebtoken::errors::ErrorKind::InvalidSignature => UserError::InvalidSignature,
+            _ => UserError::Invalid,
+        })
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for User {
+    type Error = UserError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let secret = match request.guard::<State<JwtSecret>>() {
+            Outcome::Success(secret) => secret,
+            _ => return Outcome::Failure((Status::InternalServerError, UserError::Invalid)),
+        };
+
+        let headers: Vec<_> = request.headers().get("Authorization").collect();
+        let token = match headers.len() {
+            0 => return Outcome::Failure((Status::BadRequest, UserError::Missing)),
+            1 if headers[0].starts_with("Bearer ") => headers[0].trim_start_matches("Bearer "),
+            _ => return Outcome::Failure((Status::BadRequest, UserError::Invalid)),
+        };
+
+        match decode_claims(token, &secret.0) {
+            Ok(claims) => Outcome::Success(User { id: claims.uid }),
+            Err(UserError::Expired) =>
+                Outcome::Failure((Status::Unauthorized, UserError::Expired)),
+            Err(UserError::InvalidSignature) =>
+                Outcome::Failure((Status::Unauthorized, UserError::InvalidSignature)),
+            Err(err) => Outcome::Failure((Status::Unauthorized, err)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginResponse {
+    token: String,
+}
+
+#[post("/login", format = "json", data = "<login>")]
+pub fn login(db_client: State<Client>, secret: State<JwtSecret>, login: Json<LoginRequest>)
+    -> Result<Json<LoginResponse>, Status>
+{
+    let coll = db_client.db(DATABASE).collection(USERS_COLLECTION);
+    let user_doc = coll.find_one(Some(doc!{ "username": &login.username }), None)
+        .expect("find failed")
+        .ok_or(Status::Unauthorized)?;
+
+    let password_hash = user_doc.get_str("password_hash").expect("password_hash missing");
+    if !verify(&login.password, password_hash).unwrap_or(false) {
+        return Err(Status::Unauthorized);
+    }
+    let uid = user_doc.get_i64("uid").expect("uid missing") as u64;
+
+    let token = issue_token(uid, &secret.0).map_err(|_| Status::InternalServerError)?;
+    Ok(Json(LoginResponse { token }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issues_and_decodes_a_valid_token() {
+        let token = issue_token(42, "test-secret").unwrap();
+        let claims = decode_claims(&token, "test-secret").unwrap();
+        assert_eq!(claims.uid, 42);
+    }
+
+    #[test]
+    fn rejects_token_signed_with_a_different_secret() {
+        let token = issue_token(42, "right-secret").unwrap();
+        let err = decode_claims(&token, "wrong-secret").unwrap_err();
+        assert!(matches!(err, UserError::InvalidSignature));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let claims = Claims {
+            uid: 1,
+            iat: (Utc::now() - Duration::hours(2)).timestamp(),
+            exp: (Utc::now() - Duration::hours(1)).timestamp(),
+        };
+        let token = encode(&Header::default(), &claims, "test-secret".as_ref()).unwrap();
+        let err = decode_claims(&token, "test-secret").unwrap_err();
+        assert!(matches!(err, UserError::Expired));
+    }
+
+    #[test]
+    fn rejects_malformed_token() {
+        let err = decode_claims("not-a-jwt", "test-secret").unwrap_err();
+        assert!(matches!(err, UserError::Invalid));
+    }
+}
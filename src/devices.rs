@@ -0,0 +1,14 @@
+use std::collections::HashMap;
+use std::fs;
+
+/// Static `device_id` -> human nickname mapping, loaded once at startup from a
+/// TOML config file and handed to Rocket as managed state.
+pub struct DeviceNicknames(pub HashMap<String, String>);
+
+pub fn load_nicknames(path: &str) -> DeviceNicknames {
+    let nicknames = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default();
+    DeviceNicknames(nicknames)
+}
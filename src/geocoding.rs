@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use google_geocoding::Geocoder;
+
+/// Google Maps API key used for reverse-geocoding lookups.
+pub struct GeocodingApiKey(pub String);
+
+/// Cache of resolved coordinate -> address strings, keyed on a fixed-precision
+/// lat/lng pair so repeated lookups for a stationary device don't pay for a
+/// fresh API call every time.
+pub struct AddressCache(Mutex<HashMap<(i32, i32), String>>);
+
+impl AddressCache {
+    pub fn new() -> Self {
+        AddressCache(Mutex::new(HashMap::new()))
+    }
+}
+
+fn cache_key(lat: f32, lng: f32) -> (i32, i32) {
+    ((lat * 1e5) as i32, (lng * 1e5) as i32)
+}
+
+/// Resolves a coordinate to a human-readable address, consulting (and
+/// populating) the cache before falling back to the geocoding API.
+pub fn resolve_address(cache: &AddressCache, api_key: &str, lat: f32, lng: f32) -> Option<String> {
+    let key = cache_key(lat, lng);
+    if let Some(address) = cache.0.lock().unwrap().get(&key) {
+        return Some(address.clone());
+    }
+
+    let address = Geocoder::new(api_key).reverse(lat as f64, lng as f64).ok().flatten()?;
+    cache.0.lock().unwrap().insert(key, address.clone());
+    Some(address)
+}
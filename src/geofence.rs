@@ -0,0 +1,273 @@
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+
+use google_geocoding::WGS84;
+
+use mongodb::{bson, doc, Client, ThreadedClient};
+use mongodb::coll::options::FindOptions;
+use mongodb::db::ThreadedDatabase;
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use crate::auth::User;
+use crate::DATABASE;
+
+const ZONES_COLLECTION: &'static str = "zones";
+const ZONE_EVENTS_COLLECTION: &'static str = "geofence_events";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ZoneCenter {
+    lat: f32,
+    lng: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ZoneRequest {
+    name: String,
+    center: ZoneCenter,
+    radius_m: f32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Zone {
+    id: String,
+    name: String,
+    center: ZoneCenter,
+    radius_m: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ZoneEvent {
+    Entered,
+    Exited,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ZoneTransition {
+    device_id: String,
+    zone_id: String,
+    name: String,
+    event: ZoneEvent,
+}
+
+/// A logged `geofence_events` row, as returned by `GET /zones/events`.
+#[derive(Serialize, Debug, Clone)]
+pub struct ZoneEventLogEntry {
+    device_id: String,
+    zone_id: String,
+    event: ZoneEvent,
+    timestamp: String,
+}
+
+fn zone_from_doc(doc: &bson::Document) -> Zone {
+    Zone {
+        id: doc.get_object_id("_id").expect("_id missing").to_hex(),
+        name: doc.get_str("name").expect("name missing").to_string(),
+        center: ZoneCenter {
+            lat: doc.get_f64("center_lat").expect("center_lat missing") as f32,
+            lng: doc.get_f64("center_lng").expect("center_lng missing") as f32,
+        },
+        radius_m: doc.get_f64("radius_m").expect("radius_m missing") as f32,
+    }
+}
+
+#[post("/zones", format = "json", data = "<zone>")]
+pub fn create_zone(db_client: State<Client>, user: User, zone: Json<ZoneRequest>)
+    -> Result<Json<Zone>, Status>
+{
+    if zone.radius_m <= 0.0 {
+        return Err(Status::BadRequest);
+    }
+
+    let coll = db_client.db(DATABASE).collection(ZONES_COLLECTION);
+    let insert = doc! {
+        "uid": user.id,
+        "name": &zone.name,
+        "center_lat": zone.center.lat,
+        "center_lng": zone.center.lng,
+        "radius_m": zone.radius_m,
+    };
+    let result = coll.insert_one(insert, None).expect("insert failed");
+    let id = result.inserted_id.expect("missing inserted id")
+        .as_object_id().expect("inserted id not an ObjectId")
+        .to_hex();
+
+    Ok(Json(Zone {
+        id,
+        name: zone.name.clone(),
+        center: zone.center.clone(),
+        radius_m: zone.radius_m,
+    }))
+}
+
+#[get("/zones")]
+pub fn list_zones(db_client: State<Client>, user: User) -> Json<Vec<Zone>> {
+    let coll = db_client.db(DATABASE).collection(ZONES_COLLECTION);
+    let cursor = coll.find(Some(doc! { "uid": user.id }), None).expect("find failed");
+    let zones = cursor.map(|r| zone_from_doc(&r.expect("cursor failure"))).collect();
+    Json(zones)
+}
+
+#[delete("/zones/<zone_id>")]
+pub fn delete_zone(db_client: State<Client>, user: User, zone_id: String) -> Status {
+    let oid = match bson::oid::ObjectId::with_string(&zone_id) {
+        Ok(oid) => oid,
+        Err(_) => return Status::BadRequest,
+    };
+
+    let coll = db_client.db(DATABASE).collection(ZONES_COLLECTION);
+    let result = coll.delete_one(doc! { "_id": oid, "uid": user.id }, None).expect("delete failed");
+    if result.deleted_count == 1 { Status::Ok } else { Status::NotFound }
+}
+
+fn inside(zone: &Zone, lat: f32, lng: f32) -> bool {
+    let center = WGS84::new(zone.center.lat, zone.center.lng, 0.0);
+    let point = WGS84::new(lat, lng, 0.0);
+    center.distance(&point) <= zone.radius_m
+}
+
+/// Classifies a membership flip between two fixes, or `None` if the device
+/// stayed on the same side of the boundary.
+fn transition_event(was_inside: bool, is_inside: bool) -> Option<ZoneEvent> {
+    match (was_inside, is_inside) {
+        (false, true) => Some(ZoneEvent::Entered),
+        (true, false) => Some(ZoneEvent::Exited),
+        _ => None,
+    }
+}
+
+fn event_name(event: &ZoneEvent) -> &'static str {
+    match event {
+        ZoneEvent::Entered => "Entered",
+        ZoneEvent::Exited => "Exited",
+    }
+}
+
+fn log_event(db_client: &Client, uid: u64, device_id: &str, zone_id: &str, event: &ZoneEvent) {
+    let coll = db_client.db(DATABASE).collection(ZONE_EVENTS_COLLECTION);
+    coll.insert_one(doc! {
+        "uid": uid,
+        "device_id": device_id,
+        "zone_id": zone_id,
+        "event": event_name(event),
+        "timestamp": DateTime::<Utc>::from(SystemTime::now()),
+    }, None).expect("insert failed");
+}
+
+/// Evaluates every zone registered for `uid` against the new fix from
+/// `device_id`, logging and returning any boundary crossing relative to the
+/// previous fix's position (treated as outside every zone if there was no
+/// previous fix).
+pub fn evaluate_transitions(
+    db_client: &Client,
+    uid: u64,
+    device_id: &str,
+    prev: Option<(f32, f32)>,
+    new_lat: f32,
+    new_lng: f32,
+) -> Vec<ZoneTransition> {
+    let coll = db_client.db(DATABASE).collection(ZONES_COLLECTION);
+    let cursor = coll.find(Some(doc! { "uid": uid }), None).expect("find failed");
+
+    let mut transitions = Vec::new();
+    for cursor_result in cursor {
+        let zone = zone_from_doc(&cursor_result.expect("cursor failure"));
+        let was_inside = prev.map(|(lat, lng)| inside(&zone, lat, lng)).unwrap_or(false);
+        let is_inside = inside(&zone, new_lat, new_lng);
+
+        if let Some(event) = transition_event(was_inside, is_inside) {
+            log_event(db_client, uid, device_id, &zone.id, &event);
+            transitions.push(ZoneTransition {
+                device_id: device_id.to_string(), zone_id: zone.id.clone(), name: zone.name.clone(), event,
+            });
+        }
+    }
+    transitions
+}
+
+fn zone_events_opts() -> FindOptions {
+    let mut opts = FindOptions::new();
+    opts.sort = Some(doc! { "timestamp": -1 });
+    opts
+}
+
+/// Logged zone crossings for `uid`, most recent first, optionally scoped to
+/// one device.
+#[get("/zones/events?<device_id>")]
+pub fn list_zone_events(db_client: State<Client>, user: User, device_id: Option<String>)
+    -> Json<Vec<ZoneEventLogEntry>>
+{
+    let coll = db_client.db(DATABASE).collection(ZONE_EVENTS_COLLECTION);
+    let mut query = doc! { "uid": user.id };
+    if let Some(device_id) = &device_id {
+        query.insert("device_id", device_id.as_str());
+    }
+    let cursor = coll.find(Some(query), Some(zone_events_opts())).expect("find failed");
+
+    let events = cursor.map(|cursor_result| {
+        let item = cursor_result.expect("cursor failure");
+        let event = match item.get_str("event").expect("event missing") {
+            "Entered" => ZoneEvent::Entered,
+            "Exited" => ZoneEvent::Exited,
+            other => panic!("unknown geofence event: {}", other),
+        };
+        ZoneEventLogEntry {
+            device_id: item.get_str("device_id").unwrap_or("default").to_string(),
+            zone_id: item.get_str("zone_id").expect("zone_id missing").to_string(),
+            event,
+            timestamp: item.get_utc_datetime("timestamp").expect("timestamp missing").to_rfc3339(),
+        }
+    }).collect();
+    Json(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone() -> Zone {
+        Zone {
+            id: "zone-1".to_string(),
+            name: "Home".to_string(),
+            center: ZoneCenter { lat: 40.0, lng: -105.0 },
+            radius_m: 100.0,
+        }
+    }
+
+    #[test]
+    fn point_at_center_is_inside() {
+        let z = zone();
+        assert!(inside(&z, z.center.lat, z.center.lng));
+    }
+
+    #[test]
+    fn point_far_outside_radius_is_not_inside() {
+        assert!(!inside(&zone(), 41.0, -106.0));
+    }
+
+    #[test]
+    fn point_a_kilometer_outside_radius_is_not_inside() {
+        let z = zone();
+        // roughly 0.01 degrees of latitude is over a kilometer, well past a 100m radius
+        assert!(!inside(&z, z.center.lat + 0.01, z.center.lng));
+    }
+
+    #[test]
+    fn crossing_into_zone_is_entered() {
+        assert_eq!(transition_event(false, true), Some(ZoneEvent::Entered));
+    }
+
+    #[test]
+    fn crossing_out_of_zone_is_exited() {
+        assert_eq!(transition_event(true, false), Some(ZoneEvent::Exited));
+    }
+
+    #[test]
+    fn staying_inside_or_outside_is_no_transition() {
+        assert_eq!(transition_event(true, true), None);
+        assert_eq!(transition_event(false, false), None);
+    }
+}
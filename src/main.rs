@@ -3,6 +3,12 @@
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate serde_derive;
 
+mod auth;
+mod devices;
+mod geocoding;
+mod geofence;
+mod stream;
+
 use std::error::Error;
 use std::time::SystemTime;
 
@@ -11,62 +17,54 @@ use chrono::prelude::*;
 use google_geocoding::WGS84;
 
 use rocket::{State, Outcome};
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, content, Responder, Response, Stream};
 use rocket_contrib::json::Json;
 
 use mongodb::{bson, doc, Client, ThreadedClient, ClientOptions};
 use mongodb::coll::options::FindOptions;
 use mongodb::db::ThreadedDatabase;
 
-#[derive(Debug)]
-struct User {
-    id: u64,
-}
-
-#[derive(Debug)]
-enum UserError {
-    Missing,
-    NotFound,
-    Malformed(Box<dyn std::error::Error>),
-    Invalid,
-}
-
-fn validate(key: u64) -> bool {
-    key == 111
-}
-
-impl<'a, 'r> FromRequest<'a, 'r> for User {
-    type Error = UserError;
-
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let keys: Vec<_> = request.headers().get("x-api-key").collect();
-        match keys.len() {
-            0 => Outcome::Failure((Status::BadRequest, UserError::Missing)),
-            1 => match keys[0].parse().map(|id| (validate(id), id)) {
-                    Ok((true, id)) => Outcome::Success(User { id }),
-                    Ok((false, _)) => Outcome::Failure((Status::BadRequest, UserError::Invalid)),
-                    Err(err) =>
-                        Outcome::Failure((Status::BadRequest, UserError::Malformed(Box::new(err)))),
-            },
-            _ => Outcome::Failure((Status::BadRequest, UserError::Invalid)),
-        }
-    }
+use auth::{JwtSecret, User};
+use devices::{load_nicknames, DeviceNicknames};
+use geocoding::{resolve_address, AddressCache, GeocodingApiKey};
+use geofence::ZoneTransition;
+use stream::{Hub, SseStream, StreamSlots};
+
+type LocationHub = Hub<LiveLocationEvent>;
+
+/// A single device's fix as published to `/loc/stream` subscribers — a thin
+/// wrapper around `TimestampLocation` that keeps multi-device fixes
+/// distinguishable on the live feed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LiveLocationEvent {
+    device_id: String,
+    #[serde(flatten)]
+    location: TimestampLocation,
 }
 
 const DATABASE: &'static str = "gps";
 const COLLECTION: &'static str = "locations";
+const DEFAULT_DEVICE_CONFIG: &'static str = "devices.toml";
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Upper bound on concurrently open `/loc/stream` subscriptions, kept well
+/// below the worker count configured in `Rocket.toml` so streaming clients
+/// can never pin every worker and starve ordinary requests.
+const MAX_CONCURRENT_STREAMS: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Location {
     lng: f32,
     lat: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct TimestampLocation {
     timestamp: String,
     location: Location,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -80,37 +78,134 @@ enum QueryResponse {
 
 #[derive(Serialize, Deserialize, Debug)]
 enum UpdateResponse {
-    Initial,
-    DistTraveled(Kilometers),
+    Initial {
+        zone_events: Vec<ZoneTransition>,
+    },
+    DistTraveled {
+        dist: Kilometers,
+        speed_kmh: f32,
+        state: MovementState,
+        zone_events: Vec<ZoneTransition>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum MovementState {
+    Stationary,
+    Walking,
+    Driving,
+}
+
+/// Speed bucket boundaries (km/h) and a minimum-distance jitter floor (km) below
+/// which consecutive fixes are treated as noise rather than movement.
+struct SpeedThresholds {
+    walking_kmh: f32,
+    driving_kmh: f32,
+    jitter_km: f32,
+}
+
+impl Default for SpeedThresholds {
+    fn default() -> Self {
+        SpeedThresholds { walking_kmh: 6.0, driving_kmh: 25.0, jitter_km: 0.02 }
+    }
+}
+
+fn load_speed_thresholds() -> SpeedThresholds {
+    let defaults = SpeedThresholds::default();
+    let from_env = |name: &str, default: f32| {
+        std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    };
+    SpeedThresholds {
+        walking_kmh: from_env("WALKING_KMH_THRESHOLD", defaults.walking_kmh),
+        driving_kmh: from_env("DRIVING_KMH_THRESHOLD", defaults.driving_kmh),
+        jitter_km: from_env("JITTER_KM_THRESHOLD", defaults.jitter_km),
+    }
 }
 
+fn classify_speed(speed_kmh: f32, thresholds: &SpeedThresholds) -> MovementState {
+    if speed_kmh >= thresholds.driving_kmh {
+        MovementState::Driving
+    } else if speed_kmh >= thresholds.walking_kmh {
+        MovementState::Walking
+    } else {
+        MovementState::Stationary
+    }
+}
 
-#[put("/loc", format="json", data="<location>")]
-fn update_location(db_client: State<Client>, user: User, location: Json<Location>)
-    -> Json<UpdateResponse>
+/// Speed in km/h implied by a distance and elapsed time, treating a
+/// non-advancing clock or a sub-jitter distance as noise (0 km/h) rather
+/// than reporting phantom speed for a parked device.
+fn compute_speed_kmh(dist_km: f32, elapsed_hours: f32, thresholds: &SpeedThresholds) -> f32 {
+    if dist_km < thresholds.jitter_km || elapsed_hours <= 0.0 {
+        0.0
+    } else {
+        dist_km / elapsed_hours
+    }
+}
+
+#[put("/loc/<device_id>", format="json", data="<location>")]
+fn update_location(
+    db_client: State<Client>,
+    thresholds: State<SpeedThresholds>,
+    hub: State<LocationHub>,
+    user: User,
+    device_id: String,
+    location: Json<Location>,
+) -> Json<UpdateResponse>
 {
-    // retrieve previous location
-    let last_loc = last_location(&*db_client, &user);
+    // retrieve previous location for this device
+    let last_loc = last_device_location(&*db_client, &user, &device_id);
 
     // insert new location
+    let now = SystemTime::now();
     let coll = db_client.db(DATABASE).collection(COLLECTION);
     let update = doc! {
         "uid": user.id,
-        "timestamp": DateTime::<Utc>::from(SystemTime::now()),
+        "device_id": &device_id,
+        "timestamp": DateTime::<Utc>::from(now),
         "lng": location.lng,
         "lat": location.lat,
     };
     coll.insert_one(update, None).expect("insert failed");
-    println!("Location update for {:?}: {:?}\n", user, location);
+    println!("Location update for {:?} on device {}: {:?}\n", user, device_id, location);
+
+    // push the fresh fix to anyone streaming this user's location live
+    hub.publish(user.id, LiveLocationEvent {
+        device_id: device_id.clone(),
+        location: TimestampLocation {
+            timestamp: DateTime::<Utc>::from(now).to_rfc3339(),
+            location: Location { lng: location.lng, lat: location.lat },
+            address: None,
+        },
+    });
+
+    let prev_coords = last_loc.as_ref().map(|prev| (prev.location.lat, prev.location.lng));
+    let zone_events = geofence::evaluate_transitions(
+        &*db_client, user.id, &device_id, prev_coords, location.lat, location.lng,
+    );
 
     match last_loc {
         Some(prev_loc) => {
             // compute distance traveled
-            let prev_loc = WGS84::new(prev_loc.location.lat, prev_loc.location.lng, 0.0);
-            let new_loc = WGS84::new(location.lat, location.lng, 0.0);
-            Json(UpdateResponse::DistTraveled(Kilometers(prev_loc.distance(&new_loc) / 1000.0)))
+            let prev_geo = WGS84::new(prev_loc.location.lat, prev_loc.location.lng, 0.0);
+            let new_geo = WGS84::new(location.lat, location.lng, 0.0);
+            let dist_km = prev_geo.distance(&new_geo) / 1000.0;
+
+            // compute speed from the elapsed time since the last fix
+            let prev_time = DateTime::parse_from_rfc3339(&prev_loc.timestamp)
+                .expect("stored timestamp malformed")
+                .with_timezone(&Utc);
+            let elapsed_hours = DateTime::<Utc>::from(now).signed_duration_since(prev_time)
+                .num_milliseconds() as f32 / 1000.0 / 3600.0;
+
+            let speed_kmh = compute_speed_kmh(dist_km, elapsed_hours, &thresholds);
+            let state = classify_speed(speed_kmh, &thresholds);
+
+            Json(UpdateResponse::DistTraveled {
+                dist: Kilometers(dist_km), speed_kmh, state, zone_events,
+            })
         },
-        None => Json(UpdateResponse::Initial),
+        None => Json(UpdateResponse::Initial { zone_events }),
     }
 }
 
@@ -137,15 +232,110 @@ fn last_location(db_client: &Client, user: &User) -> Option<TimestampLocation> {
             location: Location {
                 lng: item.get_f64("lng").expect("lng missing") as f32,
                 lat: item.get_f64("lat").expect("lat missing") as f32,
-            }
+            },
+            address: None,
         }
     })
 }
 
-#[get("/loc")]
-fn query_location(db_client: State<Client>, user: User) -> Json<QueryResponse> {
+/// Like `last_location`, but scoped to a single device so a multi-device
+/// user's speed/state and geofence transitions aren't computed against a
+/// different device's last fix.
+fn last_device_location(db_client: &Client, user: &User, device_id: &str) -> Option<TimestampLocation> {
+    let coll = db_client.db(DATABASE).collection(COLLECTION);
+    let mut cursor = coll.find(
+        Some(doc!{ "uid": user.id, "device_id": device_id }),
+        Some(last_loc_opts()),
+    ).expect("find failed");
+
+    cursor.next().map(|cursor_result| {
+        let item = cursor_result.expect("cursor failure");
+        let timestamp = item.get_utc_datetime("timestamp").expect("timestamp missing")
+            .to_rfc3339();
+        TimestampLocation {
+            timestamp,
+            location: Location {
+                lng: item.get_f64("lng").expect("lng missing") as f32,
+                lat: item.get_f64("lat").expect("lat missing") as f32,
+            },
+            address: None,
+        }
+    })
+}
+
+fn device_ids_opts() -> FindOptions {
+    let mut opts = FindOptions::new();
+    opts.sort = Some(doc!{ "device_id": 1, "timestamp": -1 });
+    opts
+}
+
+/// Latest fix per device, most recently reported device first.
+fn last_location_by_device(db_client: &Client, user: &User) -> Vec<(String, TimestampLocation)> {
+    let coll = db_client.db(DATABASE).collection(COLLECTION);
+    let cursor = coll.find(
+        Some(doc!{ "uid": user.id }),
+        Some(device_ids_opts()),
+    ).expect("find failed");
+
+    let mut latest: Vec<(String, TimestampLocation)> = Vec::new();
+    for cursor_result in cursor {
+        let item = cursor_result.expect("cursor failure");
+        let device_id = item.get_str("device_id").unwrap_or("default").to_string();
+        if latest.iter().any(|(id, _)| *id == device_id) {
+            continue;
+        }
+        let timestamp = item.get_utc_datetime("timestamp").expect("timestamp missing")
+            .to_rfc3339();
+        latest.push((device_id, TimestampLocation {
+            timestamp,
+            location: Location {
+                lng: item.get_f64("lng").expect("lng missing") as f32,
+                lat: item.get_f64("lat").expect("lat missing") as f32,
+            },
+            address: None,
+        }));
+    }
+
+    // `device_ids_opts` sorts by device_id first (to group each device's fixes
+    // together) and timestamp second (to pick the latest one per group), so
+    // re-sort the per-device results themselves by recency
+    latest.sort_by(|a, b| b.1.timestamp.cmp(&a.1.timestamp));
+    latest
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceInfo {
+    device_id: String,
+    nickname: Option<String>,
+    last_location: TimestampLocation,
+}
+
+#[get("/devices")]
+fn devices(db_client: State<Client>, nicknames: State<DeviceNicknames>, user: User) -> Json<Vec<DeviceInfo>> {
+    let devices = last_location_by_device(&*db_client, &user).into_iter()
+        .map(|(device_id, last_location)| {
+            let nickname = nicknames.0.get(&device_id).cloned();
+            DeviceInfo { device_id, nickname, last_location }
+        })
+        .collect();
+    Json(devices)
+}
+
+#[get("/loc?<resolve>")]
+fn query_location(
+    db_client: State<Client>,
+    geocoding_key: State<GeocodingApiKey>,
+    address_cache: State<AddressCache>,
+    user: User,
+    resolve: Option<bool>,
+) -> Json<QueryResponse> {
     match last_location(&*db_client, &user) {
-        Some(ts_loc) => {
+        Some(mut ts_loc) => {
+            if resolve.unwrap_or(false) {
+                ts_loc.address = resolve_address(
+                    &address_cache, &geocoding_key.0, ts_loc.location.lat, ts_loc.location.lng,
+                );
+            }
             println!("Last location for {}: {:?}", user.id, ts_loc);
             Json(QueryResponse::Location(ts_loc))
         },
@@ -156,11 +346,127 @@ fn query_location(db_client: State<Client>, user: User) -> Json<QueryResponse> {
     }
 }
 
+#[get("/loc/stream")]
+fn loc_stream(hub: State<LocationHub>, slots: State<StreamSlots>, user: User)
+    -> Result<content::Content<Stream<SseStream<LiveLocationEvent>>>, Status>
+{
+    // each open subscription pins a worker thread for its lifetime (see
+    // `StreamSlots`'s doc comment in stream.rs), so cap concurrent streams
+    // rather than let them exhaust the pool
+    let slot = slots.try_acquire().ok_or(Status::ServiceUnavailable)?;
+    let rx = hub.subscribe(user.id);
+    Ok(content::Content(ContentType::new("text", "event-stream"), Stream::from(SseStream::new(rx, slot))))
+}
+
+fn history_opts() -> FindOptions {
+    let mut opts = FindOptions::new();
+    opts.sort = Some(doc!{ "timestamp": 1 });
+    opts
+}
+
+fn history_query(user: &User, device_id: Option<&str>, from: Option<&str>, to: Option<&str>)
+    -> Result<bson::Document, chrono::ParseError>
+{
+    let mut query = doc!{ "uid": user.id };
+    if let Some(device_id) = device_id {
+        query.insert("device_id", device_id);
+    }
+    let mut range = bson::Document::new();
+    if let Some(from) = from {
+        range.insert("$gte", DateTime::parse_from_rfc3339(from)?.with_timezone(&Utc));
+    }
+    if let Some(to) = to {
+        range.insert("$lte", DateTime::parse_from_rfc3339(to)?.with_timezone(&Utc));
+    }
+    if !range.is_empty() {
+        query.insert("timestamp", range);
+    }
+    Ok(query)
+}
+
+fn location_history(db_client: &Client, user: &User, device_id: Option<&str>, from: Option<&str>, to: Option<&str>)
+    -> Result<Vec<(String, TimestampLocation)>, chrono::ParseError>
+{
+    let coll = db_client.db(DATABASE).collection(COLLECTION);
+    let query = history_query(user, device_id, from, to)?;
+    let cursor = coll.find(Some(query), Some(history_opts())).expect("find failed");
+
+    Ok(cursor.map(|cursor_result| {
+        let item = cursor_result.expect("cursor failure");
+        let device_id = item.get_str("device_id").unwrap_or("default").to_string();
+        let timestamp = item.get_utc_datetime("timestamp").expect("timestamp missing")
+            .to_rfc3339();
+        (device_id, TimestampLocation {
+            timestamp,
+            location: Location {
+                lng: item.get_f64("lng").expect("lng missing") as f32,
+                lat: item.get_f64("lat").expect("lat missing") as f32,
+            },
+            address: None,
+        })
+    }).collect())
+}
+
+/// Splits a timestamp-ordered fix list into one run per device, preserving
+/// each device's first-appearance order, so a multi-device export renders as
+/// one clean track segment per device instead of one zig-zagging between
+/// devices.
+fn group_by_device(fixes: Vec<(String, TimestampLocation)>) -> Vec<(String, Vec<TimestampLocation>)> {
+    let mut groups: Vec<(String, Vec<TimestampLocation>)> = Vec::new();
+    for (device_id, loc) in fixes {
+        match groups.iter_mut().find(|(id, _)| *id == device_id) {
+            Some((_, locs)) => locs.push(loc),
+            None => groups.push((device_id, vec![loc])),
+        }
+    }
+    groups
+}
+
+/// A GPX 1.1 document, served with an `application/gpx+xml` content type.
+struct Gpx(String);
+
+impl<'r> Responder<'r> for Gpx {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(req)?)
+            .header(ContentType::new("application", "gpx+xml"))
+            .ok()
+    }
+}
+
+fn render_gpx(tracks: &[(String, Vec<TimestampLocation>)]) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"gps-tracker\">\n<trk>\n"
+    );
+    for (_, locations) in tracks {
+        gpx.push_str("<trkseg>\n");
+        for loc in locations {
+            gpx.push_str(&format!(
+                "<trkpt lat=\"{}\" lon=\"{}\"><time>{}</time></trkpt>\n",
+                loc.location.lat, loc.location.lng, loc.timestamp,
+            ));
+        }
+        gpx.push_str("</trkseg>\n");
+    }
+    gpx.push_str("</trk></gpx>\n");
+    gpx
+}
+
+#[get("/track.gpx?<device_id>&<from>&<to>")]
+fn track(db_client: State<Client>, user: User, device_id: Option<String>, from: Option<String>, to: Option<String>)
+    -> Result<Gpx, Status>
+{
+    let fixes = location_history(&*db_client, &user, device_id.as_deref(), from.as_deref(), to.as_deref())
+        .map_err(|_| Status::BadRequest)?;
+    Ok(Gpx(render_gpx(&group_by_device(fixes))))
+}
+
 #[derive(Debug)]
 pub enum ArgError {
     Url,
     User,
     Pass,
+    Secret,
 }
 impl std::fmt::Display for ArgError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -173,6 +479,7 @@ impl Error for ArgError {
             ArgError::Url => "missing argument: URL",
             ArgError::User => "missing argument: User",
             ArgError::Pass => "missing argument: Password",
+            ArgError::Secret => "missing argument: JWT secret (or JWT_SECRET env var)",
         }
     }
 }
@@ -184,6 +491,13 @@ fn main() -> Result<(), Box<dyn Error>>{
     let url = args_iter.next().ok_or(ArgError::Url)?;
     let user = args_iter.next().ok_or(ArgError::User)?;
     let pass = args_iter.next().ok_or(ArgError::Pass)?;
+    let jwt_secret = args_iter.next()
+        .or_else(|| std::env::var("JWT_SECRET").ok())
+        .ok_or(ArgError::Secret)?;
+    let device_config = args_iter.next().unwrap_or_else(|| DEFAULT_DEVICE_CONFIG.to_string());
+    let geocoding_key = args_iter.next()
+        .or_else(|| std::env::var("GEOCODING_API_KEY").ok())
+        .unwrap_or_default();
 
     let client = Client::with_uri_and_options(
         &url,
@@ -194,10 +508,69 @@ fn main() -> Result<(), Box<dyn Error>>{
     let db = client.db("admin");
     db.auth(&user, &pass)?;
 
+    client.db(DATABASE).collection(COLLECTION)
+        .create_index(doc!{ "uid": 1, "device_id": 1, "timestamp": 1 }, None)
+        .expect("failed to create index");
+
     rocket::ignite()
-        .mount("/", routes![update_location, query_location])
+        .mount("/", routes![
+            update_location, query_location, track, devices, loc_stream, auth::login,
+            geofence::create_zone, geofence::list_zones, geofence::delete_zone, geofence::list_zone_events,
+        ])
         .manage(client)
+        .manage(JwtSecret(jwt_secret))
+        .manage(load_nicknames(&device_config))
+        .manage(load_speed_thresholds())
+        .manage(GeocodingApiKey(geocoding_key))
+        .manage(AddressCache::new())
+        .manage(LocationHub::new())
+        .manage(StreamSlots::new(MAX_CONCURRENT_STREAMS))
         .launch();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> SpeedThresholds {
+        SpeedThresholds { walking_kmh: 6.0, driving_kmh: 25.0, jitter_km: 0.02 }
+    }
+
+    #[test]
+    fn classifies_below_walking_threshold_as_stationary() {
+        assert_eq!(classify_speed(2.0, &thresholds()), MovementState::Stationary);
+    }
+
+    #[test]
+    fn classifies_at_walking_threshold_as_walking() {
+        assert_eq!(classify_speed(6.0, &thresholds()), MovementState::Walking);
+    }
+
+    #[test]
+    fn classifies_just_below_driving_threshold_as_walking() {
+        assert_eq!(classify_speed(24.9, &thresholds()), MovementState::Walking);
+    }
+
+    #[test]
+    fn classifies_at_driving_threshold_as_driving() {
+        assert_eq!(classify_speed(25.0, &thresholds()), MovementState::Driving);
+    }
+
+    #[test]
+    fn sub_jitter_distance_reports_zero_speed() {
+        assert_eq!(compute_speed_kmh(0.01, 1.0, &thresholds()), 0.0);
+    }
+
+    #[test]
+    fn non_advancing_clock_reports_zero_speed() {
+        assert_eq!(compute_speed_kmh(1.0, 0.0, &thresholds()), 0.0);
+        assert_eq!(compute_speed_kmh(1.0, -1.0, &thresholds()), 0.0);
+    }
+
+    #[test]
+    fn normal_movement_computes_distance_over_time() {
+        assert_eq!(compute_speed_kmh(10.0, 0.5, &thresholds()), 20.0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// A Rocket worker thread blocks in `SseStream::read` for the entire life of
+/// a `/loc/stream` subscription (this Rocket version is pre-async and has no
+/// way to park a connection off its fixed-size worker pool), so the pool must
+/// be sized with streaming clients in mind and subscriptions must be capped
+/// well below the worker count — otherwise a handful of open dashboards can
+/// pin every worker and stall ordinary requests like `PUT /loc`. See
+/// `Rocket.toml` for the worker count this cap is sized against.
+pub struct StreamSlots {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl StreamSlots {
+    pub fn new(max: usize) -> Self {
+        StreamSlots { count: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    /// Reserves one subscription slot, returning `None` if the cap has been
+    /// reached. The slot is released automatically when the returned guard
+    /// is dropped (i.e. when the subscriber's connection ends).
+    pub fn try_acquire(&self) -> Option<StreamSlotGuard> {
+        loop {
+            let current = self.count.load(Ordering::SeqCst);
+            if current >= self.max {
+                return None;
+            }
+            if self.count.compare_exchange(
+                current, current + 1, Ordering::SeqCst, Ordering::SeqCst,
+            ).is_ok() {
+                return Some(StreamSlotGuard(self.count.clone()));
+            }
+        }
+    }
+}
+
+pub struct StreamSlotGuard(Arc<AtomicUsize>);
+
+impl Drop for StreamSlotGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Per-key fan-out broadcast hub: each subscriber gets its own channel, and a
+/// publish pushes a clone of the message to every live subscriber for that
+/// key, dropping any whose receiver has since gone away.
+pub struct Hub<T>(Mutex<HashMap<u64, Vec<Sender<T>>>>);
+
+impl<T: Clone> Hub<T> {
+    pub fn new() -> Self {
+        Hub(Mutex::new(HashMap::new()))
+    }
+
+    pub fn subscribe(&self, key: u64) -> Receiver<T> {
+        let (tx, rx) = channel();
+        self.0.lock().unwrap().entry(key).or_insert_with(Vec::new).push(tx);
+        rx
+    }
+
+    pub fn publish(&self, key: u64, msg: T) {
+        let mut subscribers = self.0.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(&key) {
+            senders.retain(|tx| tx.send(msg.clone()).is_ok());
+        }
+    }
+}
+
+/// Adapts a channel of serializable events into a `Read` stream of
+/// Server-Sent Events, interleaving periodic keep-alive comments so
+/// intermediaries don't time out an idle connection.
+pub struct SseStream<T> {
+    rx: Receiver<T>,
+    buffer: Vec<u8>,
+    // held only to release the slot on drop
+    _slot: StreamSlotGuard,
+}
+
+impl<T: Serialize> SseStream<T> {
+    pub fn new(rx: Receiver<T>, slot: StreamSlotGuard) -> Self {
+        SseStream { rx, buffer: Vec::new(), _slot: slot }
+    }
+}
+
+impl<T: Serialize> Read for SseStream<T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match self.rx.recv_timeout(KEEPALIVE) {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                    self.buffer = format!("data: {}\n\n", json).into_bytes();
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    self.buffer = b": keep-alive\n\n".to_vec();
+                },
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let n = std::cmp::min(out.len(), self.buffer.len());
+        out[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        Ok(n)
+    }
+}